@@ -1,21 +1,49 @@
 use std::time::Instant;
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use wgpu::RenderPassTimestampWrites;
 
+use crate::statistics::StatisticsRecorder;
+
+mod statistics;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum BackendSelection {
+    Vulkan,
+    Metal,
+    Dx12,
+    Gl,
+    All,
+}
+
+impl BackendSelection {
+    fn to_backends(self) -> wgpu::Backends {
+        match self {
+            BackendSelection::Vulkan => wgpu::Backends::VULKAN,
+            BackendSelection::Metal => wgpu::Backends::METAL,
+            BackendSelection::Dx12 => wgpu::Backends::DX12,
+            BackendSelection::Gl => wgpu::Backends::GL,
+            BackendSelection::All => wgpu::Backends::all(),
+        }
+    }
+}
+
 #[derive(Debug, Parser)]
 struct Args {
+    #[clap(long, default_value = "vulkan")]
+    backend: BackendSelection,
+
     #[clap(long)]
-    query_stats: bool,
+    pass_times: bool,
 
     #[clap(long)]
-    query_times: bool,
+    pass_stats: bool,
 
     #[clap(long)]
-    pass_times: bool,
+    compute_times: bool,
 
     #[clap(long)]
-    pass_stats: bool,
+    compute_stats: bool,
 }
 
 fn main() {
@@ -26,70 +54,47 @@ fn main() {
     let args = Args::parse();
 
     // setup wgpu::{Instance, Device, Queue}
-    let backend = Backend::new();
-
-    // setup pipeline statistics and timestamps queries
-    let pipeline_statistics_query_set =
-        backend.device.create_query_set(&wgpu::QuerySetDescriptor {
-            label: Some("pipeline statistics"),
-            ty: wgpu::QueryType::PipelineStatistics(wgpu::PipelineStatisticsTypes::all()),
-            count: 1,
-        });
-    let timestamps_query_set = backend.device.create_query_set(&wgpu::QuerySetDescriptor {
-        label: Some("timestamps"),
-        ty: wgpu::QueryType::Timestamp,
-        count: 3,
-    });
+    let backend = Backend::new(args.backend.to_backends());
 
-    // buffer for query resolution
-    let buffer = backend.device.create_buffer(&wgpu::BufferDescriptor {
-        label: None,
-        size: wgpu::QUERY_RESOLVE_BUFFER_ALIGNMENT * 2,
-        usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
-        mapped_at_creation: false,
-    });
+    // one recorder owns the query sets and resolve buffer and is reused across
+    // every pass this frame
+    let mut recorder = StatisticsRecorder::new(&backend);
 
     if args.pass_stats || args.pass_times {
-        // do a render pass with timestamp writes and a pipeline statistics query
-        do_render_pass(
-            &backend,
-            args.pass_times.then_some(&timestamps_query_set),
-            args.pass_stats.then_some(&pipeline_statistics_query_set),
-        );
+        // do a render pass, isolating timestamp writes and/or the pipeline
+        // statistics query according to the flags
+        do_render_pass(&backend, &mut recorder, args.pass_times, args.pass_stats);
     }
 
-    // resolve queries
-    let mut command_encoder = backend.device.create_command_encoder(&Default::default());
-    if args.query_stats {
-        tracing::debug!("resolving pipeline statistics");
-        command_encoder.resolve_query_set(&pipeline_statistics_query_set, 0..1, &buffer, 0);
-    }
-    if args.query_times {
-        tracing::debug!("resolving timestamps");
-        command_encoder.resolve_query_set(
-            &timestamps_query_set,
-            0..1,
-            &buffer,
-            wgpu::QUERY_RESOLVE_BUFFER_ALIGNMENT,
-        );
+    if args.compute_stats || args.compute_times {
+        // same for the compute path
+        do_compute_pass(&backend, &mut recorder, args.compute_times, args.compute_stats);
     }
 
+    // resolve and read back everything the recorder collected
+    let mut command_encoder = backend.device.create_command_encoder(&Default::default());
+    recorder.finish(&mut command_encoder);
     backend.submit_and_wait(command_encoder);
+    recorder.map_and_report(&backend.device);
 }
 
 #[derive(Clone, Debug)]
 pub struct Backend {
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
+    /// Whether timestamp writes inside passes are available on this device.
+    pub timestamp_queries: bool,
+    /// Whether pipeline-statistics queries are available on this device.
+    pub pipeline_statistics: bool,
 }
 
 impl Backend {
-    pub fn new() -> Self {
+    pub fn new(backends: wgpu::Backends) -> Self {
         let instance_flags = wgpu::InstanceFlags::from_build_config().with_env();
         tracing::debug!(?instance_flags);
 
         let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor {
-            backends: wgpu::Backends::VULKAN,
+            backends,
             ..Default::default()
         });
 
@@ -101,11 +106,20 @@ impl Backend {
         let adapter_info = adapter.get_info();
         tracing::debug!("adapter: {adapter_info:#?}");
 
-        let features = wgpu::Features::TIMESTAMP_QUERY
+        let requested = wgpu::Features::TIMESTAMP_QUERY
             | wgpu::Features::TIMESTAMP_QUERY_INSIDE_ENCODERS
             | wgpu::Features::TIMESTAMP_QUERY_INSIDE_PASSES
             | wgpu::Features::PIPELINE_STATISTICS_QUERY;
 
+        // only enable the query features the adapter actually supports, and log
+        // the ones we had to drop so the caller knows what's missing
+        let available = adapter.features();
+        let features = requested & available;
+        let dropped = requested - available;
+        if !dropped.is_empty() {
+            tracing::warn!(?dropped, "query features unsupported by this backend");
+        }
+
         let (device, queue) = pollster::block_on(adapter.request_device(
             &wgpu::DeviceDescriptor {
                 label: None,
@@ -116,12 +130,25 @@ impl Backend {
         ))
         .expect("could not open device");
 
+        // log uncaptured errors instead of aborting: on adapters missing some
+        // query features the point of the repro is to keep running and report
+        // what it could measure, not to panic on the first complaint
         device.on_uncaptured_error(Box::new(|error| {
             tracing::error!(%error, "uncaptured wgpu error");
-            panic!("uncaptured wgpu error: {error}");
         }));
 
-        Self { device, queue }
+        // timestamp writes inside a pass need both the base timestamp feature
+        // and the inside-passes feature
+        let timestamp_queries = features.contains(wgpu::Features::TIMESTAMP_QUERY)
+            && features.contains(wgpu::Features::TIMESTAMP_QUERY_INSIDE_PASSES);
+        let pipeline_statistics = features.contains(wgpu::Features::PIPELINE_STATISTICS_QUERY);
+
+        Self {
+            device,
+            queue,
+            timestamp_queries,
+            pipeline_statistics,
+        }
     }
 
     pub fn submit_and_wait(&self, command_encoder: wgpu::CommandEncoder) {
@@ -136,11 +163,14 @@ impl Backend {
 
 fn do_render_pass(
     backend: &Backend,
-    timestamp_query: Option<&wgpu::QuerySet>,
-    pipeline_statistics_query: Option<&wgpu::QuerySet>,
+    recorder: &mut StatisticsRecorder,
+    timestamps: bool,
+    statistics: bool,
 ) {
     tracing::debug!("render pass");
 
+    let slot = recorder.begin_span("render pass", timestamps, statistics);
+
     let mut command_encoder =
         backend
             .device
@@ -177,21 +207,126 @@ fn do_render_pass(
             },
         })],
         depth_stencil_attachment: None,
-        timestamp_writes: timestamp_query.map(|timestamp_query| {
-            RenderPassTimestampWrites {
-                query_set: timestamp_query,
-                beginning_of_pass_write_index: Some(0),
-                end_of_pass_write_index: Some(1),
-            }
+        timestamp_writes: slot.timestamps.map(|timestamps| RenderPassTimestampWrites {
+            query_set: recorder.timestamp_query_set().unwrap(),
+            beginning_of_pass_write_index: Some(timestamps.begin),
+            end_of_pass_write_index: Some(timestamps.end),
         }),
         occlusion_query_set: None,
     });
+    if timestamps && slot.timestamps.is_none() {
+        tracing::warn!("timestamp queries unavailable; skipping render pass timestamps");
+    }
 
-    if let Some(pipeline_statistics_query) = pipeline_statistics_query {
-        render_pass.begin_pipeline_statistics_query(&pipeline_statistics_query, 0);
+    if let Some(statistics_index) = slot.statistics_index {
+        render_pass.begin_pipeline_statistics_query(
+            recorder.pipeline_statistics_query_set().unwrap(),
+            statistics_index,
+        );
         render_pass.end_pipeline_statistics_query();
+    } else if statistics {
+        tracing::warn!("pipeline statistics unavailable; skipping render pass statistics");
     }
 
     drop(render_pass);
     backend.submit_and_wait(command_encoder);
+    recorder.end_span();
+}
+
+fn do_compute_pass(
+    backend: &Backend,
+    recorder: &mut StatisticsRecorder,
+    timestamps: bool,
+    statistics: bool,
+) {
+    tracing::debug!("compute pass");
+
+    let slot = recorder.begin_span("compute pass", timestamps, statistics);
+
+    // a trivial compute shader that writes into a storage buffer so the
+    // dispatch isn't optimized away
+    let shader = backend
+        .device
+        .create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("compute"),
+            source: wgpu::ShaderSource::Wgsl(
+                r#"
+@group(0) @binding(0)
+var<storage, read_write> data: array<u32>;
+
+@compute @workgroup_size(1)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    data[id.x] = data[id.x] + 1u;
+}
+"#
+                .into(),
+            ),
+        });
+
+    let storage = backend.device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("compute storage"),
+        size: 4,
+        usage: wgpu::BufferUsages::STORAGE,
+        mapped_at_creation: false,
+    });
+
+    let pipeline = backend
+        .device
+        .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("compute"),
+            layout: None,
+            module: &shader,
+            entry_point: Some("main"),
+            compilation_options: Default::default(),
+            cache: None,
+        });
+
+    let bind_group = backend.device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("compute"),
+        layout: &pipeline.get_bind_group_layout(0),
+        entries: &[wgpu::BindGroupEntry {
+            binding: 0,
+            resource: storage.as_entire_binding(),
+        }],
+    });
+
+    let mut command_encoder =
+        backend
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("compute pass"),
+            });
+
+    let mut compute_pass = command_encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+        label: Some("compute pass"),
+        timestamp_writes: slot.timestamps.map(|timestamps| wgpu::ComputePassTimestampWrites {
+            query_set: recorder.timestamp_query_set().unwrap(),
+            beginning_of_pass_write_index: Some(timestamps.begin),
+            end_of_pass_write_index: Some(timestamps.end),
+        }),
+    });
+    if timestamps && slot.timestamps.is_none() {
+        tracing::warn!("timestamp queries unavailable; skipping compute pass timestamps");
+    }
+
+    if let Some(statistics_index) = slot.statistics_index {
+        compute_pass.begin_pipeline_statistics_query(
+            recorder.pipeline_statistics_query_set().unwrap(),
+            statistics_index,
+        );
+    } else if statistics {
+        tracing::warn!("pipeline statistics unavailable; skipping compute pass statistics");
+    }
+
+    compute_pass.set_pipeline(&pipeline);
+    compute_pass.set_bind_group(0, &bind_group, &[]);
+    compute_pass.dispatch_workgroups(1, 1, 1);
+
+    if slot.statistics_index.is_some() {
+        compute_pass.end_pipeline_statistics_query();
+    }
+
+    drop(compute_pass);
+    backend.submit_and_wait(command_encoder);
+    recorder.end_span();
 }